@@ -17,6 +17,17 @@ use crate::gl::types::*;
 use crate::renderer::shader::{ShaderError, ShaderProgram, ShaderVersion};
 use crate::renderer::{self, cstr};
 
+/// Corner of a `RoundedBg` or `Outline` rect that should have its radius applied, rather than
+/// being left square. Bits are independent, so a rect can round any subset of its four corners.
+pub mod corner {
+    pub const TOP_LEFT: u8 = 0b0001;
+    pub const TOP_RIGHT: u8 = 0b0010;
+    pub const BOTTOM_LEFT: u8 = 0b0100;
+    pub const BOTTOM_RIGHT: u8 = 0b1000;
+    pub const ALL: u8 = TOP_LEFT | TOP_RIGHT | BOTTOM_LEFT | BOTTOM_RIGHT;
+    pub const NONE: u8 = 0;
+}
+
 #[derive(Debug, Copy, Clone)]
 pub struct RenderRect {
     pub x: f32,
@@ -26,11 +37,33 @@ pub struct RenderRect {
     pub color: Rgb,
     pub alpha: f32,
     pub kind: RectKind,
+
+    /// Mask of corners to round, only meaningful for `RectKind::RoundedBg`/`RectKind::Outline`.
+    pub corners: u8,
 }
 
 impl RenderRect {
     pub fn new(x: f32, y: f32, width: f32, height: f32, color: Rgb, alpha: f32) -> Self {
-        RenderRect { kind: RectKind::Underline, x, y, width, height, color, alpha }
+        RenderRect {
+            kind: RectKind::Underline,
+            x,
+            y,
+            width,
+            height,
+            color,
+            alpha,
+            corners: corner::NONE,
+        }
+    }
+
+    /// Create a rounded-outline rect boxing a span of cells, without filling over their
+    /// content. Useful for e.g. highlighting regex search matches or hint-mode labels.
+    pub fn outline(x: f32, y: f32, width: f32, height: f32, color: Rgb, alpha: f32) -> Self {
+        RenderRect {
+            kind: RectKind::Outline,
+            corners: corner::ALL,
+            ..Self::new(x, y, width, height, color, alpha)
+        }
     }
 }
 
@@ -39,6 +72,15 @@ pub struct RenderLine {
     pub start: Point<usize>,
     pub end: Point<usize>,
     pub color: Rgb,
+
+    /// Whether this line continues across a wrapped row boundary, as opposed to spanning
+    /// multiple lines some other way. Only wrapped runs should treat the wrap boundary as
+    /// something other than a real edge when rounding `RoundedBg` corners.
+    pub wrapped: bool,
+
+    /// Whether the cell at `end` carries the terminal's own line-wrap flag, i.e. whether a
+    /// cell at the start of the next line should be considered a continuation of this run.
+    end_wraps: bool,
 }
 
 // NOTE: These flags must be in sync with their usage in the rect.*.glsl shaders.
@@ -50,7 +92,8 @@ pub enum RectKind {
     UnderDotted = 2,
     UnderDashed = 3,
     RoundedBg = 4,
-    NumKinds = 5,
+    Outline = 5,
+    NumKinds = 6,
 }
 
 impl RenderLine {
@@ -60,15 +103,24 @@ impl RenderLine {
         let mut start = self.start;
         while start.line < self.end.line {
             let end = Point::new(start.line, size.last_column());
-            Self::push_rects(&mut rects, metrics, size, flag, start, end, self.color);
+            let is_first = start == self.start;
+            Self::push_rects(
+                &mut rects, metrics, size, flag, start, end, self.color, self.wrapped, is_first,
+                false,
+            );
             start = Point::new(start.line + 1, Column(0));
         }
-        Self::push_rects(&mut rects, metrics, size, flag, start, self.end, self.color);
+        let is_first = start == self.start;
+        Self::push_rects(
+            &mut rects, metrics, size, flag, start, self.end, self.color, self.wrapped, is_first,
+            true,
+        );
 
         rects
     }
 
     /// Push all rects required to draw the cell's line.
+    #[allow(clippy::too_many_arguments)]
     fn push_rects(
         rects: &mut Vec<RenderRect>,
         metrics: &Metrics,
@@ -77,6 +129,9 @@ impl RenderLine {
         start: Point<usize>,
         end: Point<usize>,
         color: Rgb,
+        wrapped: bool,
+        is_first: bool,
+        is_last: bool,
     ) {
         let (position, thickness, ty) = match flag {
             Flags::DOUBLE_UNDERLINE => {
@@ -118,6 +173,24 @@ impl RenderLine {
         let mut rect =
             Self::create_rect(size, metrics.descent, start, end, position, thickness, color);
         rect.kind = ty;
+        if ty == RectKind::RoundedBg {
+            rect.corners = match (wrapped, is_first, is_last) {
+                // Runs that don't continue across a wrap round every corner of every segment,
+                // since each segment is a complete shape of its own. `wrapped` only ever gets
+                // set once a run has split into at least two segments, so a wrapped run is
+                // never both first and last at once.
+                (false, ..) => corner::ALL,
+                // A wrapped run's first segment only rounds its top-left; its bottom continues
+                // into the next line across the wrap boundary.
+                (true, true, false) => corner::TOP_LEFT,
+                // A wrapped run's last segment only rounds its bottom-right; its top continues
+                // from the previous line across the wrap boundary.
+                (true, false, true) => corner::BOTTOM_RIGHT,
+                // Interior segments of a wrapped run are square on every side.
+                (true, false, false) => corner::NONE,
+                (true, true, true) => unreachable!("a wrapped run always has multiple segments"),
+            };
+        }
         rects.push(rect);
     }
 
@@ -207,20 +280,33 @@ impl RenderLines {
             end.column += 1;
         }
 
+        // Whether this cell is the last column of a row that actually soft-wraps, as opposed
+        // to one that merely happens to reach the last column.
+        let end_wraps = cell.flags.contains(Flags::WRAPLINE);
+
         // Check if there's an active line.
         if let Some(line) = self.inner.get_mut(&flag).and_then(|lines| lines.last_mut()) {
-            if color == line.color
-                && cell.point.column == line.end.column + 1
-                && cell.point.line == line.end.line
-            {
-                // Update the length of the line.
+            let continues_on_same_line = cell.point.column == line.end.column + 1
+                && cell.point.line == line.end.line;
+
+            // The run only continues across a row boundary when the previous line's end cell
+            // carries the terminal's own wrap flag and the new cell picks back up at the start
+            // of the very next line; two runs that merely end/start at those positions by
+            // coincidence must not be fused together.
+            let continues_on_wrapped_line = line.end_wraps
+                && cell.point.column == Column(0)
+                && cell.point.line == line.end.line + 1;
+
+            if color == line.color && (continues_on_same_line || continues_on_wrapped_line) {
                 line.end = end;
+                line.end_wraps = end_wraps;
+                line.wrapped |= continues_on_wrapped_line;
                 return;
             }
         }
 
         // Start new line if there currently is none.
-        let line = RenderLine { start: cell.point, end, color };
+        let line = RenderLine { start: cell.point, end, color, wrapped: false, end_wraps };
         match self.inner.get_mut(&flag) {
             Some(lines) => lines.push(line),
             None => {
@@ -246,6 +332,18 @@ struct Vertex {
     g: u8,
     b: u8,
     a: u8,
+
+    // Half-size of the rect, in pixels; constant across all four corners.
+    half_size_x: f32,
+    half_size_y: f32,
+
+    // Fragment's position within the rect, in pixels, ranging from `(0, 0)`
+    // at one corner to `(width, height)` at the opposite corner.
+    local_x: f32,
+    local_y: f32,
+
+    // Mask of corners to round, see `corner`; constant across all four vertices.
+    corners: f32,
 }
 
 #[derive(Debug)]
@@ -254,29 +352,53 @@ pub struct RectRenderer {
     vao: GLuint,
     vbo: GLuint,
 
+    /// Radius of rounded corners drawn by the `RoundedBg`/`Outline` shaders, in pixels.
+    corner_radius: f32,
+
+    /// Half the thickness of the ring drawn by the `Outline` shader, in pixels; the ring spans
+    /// twice this value inward from the rect's edge.
+    border_thickness: f32,
+
+    /// Whether each `RectKind` fell back to the plain underline program because its own
+    /// shader failed to compile, e.g. due to ALU instruction limits on older GPUs.
+    fallback: [bool; RectKind::NumKinds as usize],
+
     programs: [RectShaderProgram; RectKind::NumKinds as usize],
     vertices: [Vec<Vertex>; RectKind::NumKinds as usize],
 }
 
 impl RectRenderer {
-    pub fn new(shader_version: ShaderVersion) -> Result<Self, renderer::Error> {
+    pub fn new(
+        shader_version: ShaderVersion,
+        corner_radius: f32,
+        border_thickness: f32,
+    ) -> Result<Self, renderer::Error> {
         let mut vao: GLuint = 0;
         let mut vbo: GLuint = 0;
 
+        let mut fallback = [false; RectKind::NumKinds as usize];
+
         let under_line_program = RectShaderProgram::new(shader_version, RectKind::Underline)?;
-        let under_curl_program = RectShaderProgram::new(shader_version, RectKind::Undercurl)?;
-        // This shader has way more ALU operations than other rect shaders, so use a fallback
-        // to underline just for it when we can't compile it.
-        let under_dotted_program = match RectShaderProgram::new(shader_version, RectKind::UnderDotted)
-        {
-            Ok(under_dotted_program) => under_dotted_program,
-            Err(err) => {
-                info!("Error compiling dotted shader: {err}\n  falling back to underline");
-                RectShaderProgram::new(shader_version, RectKind::Underline)?
-            },
+
+        // Some of the rect shaders have way more ALU operations than the plain underline one,
+        // so fall back to underline for whichever of them fails to compile rather than refusing
+        // to start the terminal on GPUs that can't handle the heavier variants.
+        let mut compile_or_fallback = |kind: RectKind| -> Result<RectShaderProgram, renderer::Error> {
+            match RectShaderProgram::new(shader_version, kind) {
+                Ok(program) => Ok(program),
+                Err(err) => {
+                    info!("Error compiling {kind:?} shader: {err}\n  falling back to underline");
+                    fallback[kind as usize] = true;
+                    Ok(RectShaderProgram::new(shader_version, RectKind::Underline)?)
+                },
+            }
         };
-        let under_dashed_program = RectShaderProgram::new(shader_version, RectKind::UnderDashed)?;
-        let rounded_background_program = RectShaderProgram::new(shader_version, RectKind::RoundedBg)?;
+
+        let under_curl_program = compile_or_fallback(RectKind::Undercurl)?;
+        let under_dotted_program = compile_or_fallback(RectKind::UnderDotted)?;
+        let under_dashed_program = compile_or_fallback(RectKind::UnderDashed)?;
+        let rounded_background_program = compile_or_fallback(RectKind::RoundedBg)?;
+        let outline_program = compile_or_fallback(RectKind::Outline)?;
 
         unsafe {
             // Allocate buffers.
@@ -312,6 +434,42 @@ impl RectRenderer {
                 attribute_offset as *const _,
             );
             gl::EnableVertexAttribArray(1);
+            attribute_offset += mem::size_of::<u8>() * 4;
+
+            // Rect half-size.
+            gl::VertexAttribPointer(
+                2,
+                2,
+                gl::FLOAT,
+                gl::FALSE,
+                mem::size_of::<Vertex>() as i32,
+                attribute_offset as *const _,
+            );
+            gl::EnableVertexAttribArray(2);
+            attribute_offset += mem::size_of::<f32>() * 2;
+
+            // Fragment's local position within the rect.
+            gl::VertexAttribPointer(
+                3,
+                2,
+                gl::FLOAT,
+                gl::FALSE,
+                mem::size_of::<Vertex>() as i32,
+                attribute_offset as *const _,
+            );
+            gl::EnableVertexAttribArray(3);
+            attribute_offset += mem::size_of::<f32>() * 2;
+
+            // Corner rounding mask.
+            gl::VertexAttribPointer(
+                4,
+                1,
+                gl::FLOAT,
+                gl::FALSE,
+                mem::size_of::<Vertex>() as i32,
+                attribute_offset as *const _,
+            );
+            gl::EnableVertexAttribArray(4);
 
             // Reset buffer bindings.
             gl::BindVertexArray(0);
@@ -324,8 +482,27 @@ impl RectRenderer {
             under_dotted_program,
             under_dashed_program,
             rounded_background_program,
+            outline_program,
         ];
-        Ok(Self { vao, vbo, programs, vertices: Default::default() })
+        Ok(Self {
+            vao,
+            vbo,
+            corner_radius,
+            border_thickness,
+            fallback,
+            programs,
+            vertices: Default::default(),
+        })
+    }
+
+    /// Update the corner radius used for the `RoundedBg` shader.
+    pub fn update_corner_radius(&mut self, corner_radius: f32) {
+        self.corner_radius = corner_radius;
+    }
+
+    /// Update the border thickness used for the `Outline` shader.
+    pub fn update_border_thickness(&mut self, border_thickness: f32) {
+        self.border_thickness = border_thickness;
     }
 
     pub fn draw(&mut self, size_info: &SizeInfo, metrics: &Metrics, rects: Vec<RenderRect>) {
@@ -357,7 +534,20 @@ impl RectRenderer {
 
                 let program = &self.programs[rect_kind as usize];
                 gl::UseProgram(program.id());
-                program.update_uniforms(size_info, metrics);
+
+                if self.fallback[rect_kind as usize] {
+                    // This kind's specialized shader failed to compile, so its program slot
+                    // holds the plain underline program instead; draw it as a plain opaque
+                    // rect, without the corner radius or border thickness it has no use for.
+                    program.update_uniforms(size_info, metrics, 0., 0.);
+                } else {
+                    program.update_uniforms(
+                        size_info,
+                        metrics,
+                        self.corner_radius,
+                        self.border_thickness,
+                    );
+                }
 
                 // Upload accumulated undercurl vertices.
                 gl::BufferData(
@@ -390,12 +580,67 @@ impl RectRenderer {
         let (r, g, b) = rect.color.as_tuple();
         let a = (rect.alpha * 255.) as u8;
 
+        // Half-size and local position are expressed in pixels, rather than normalized
+        // device coordinates, so that the corner radius uniform (also in pixels) produces
+        // a round corner regardless of window size or aspect ratio.
+        let half_size_x = rect.width / 2.;
+        let half_size_y = rect.height / 2.;
+        let corners = rect.corners as f32;
+
         // Make quad vertices.
         let quad = [
-            Vertex { x, y, r, g, b, a },
-            Vertex { x, y: y - height, r, g, b, a },
-            Vertex { x: x + width, y, r, g, b, a },
-            Vertex { x: x + width, y: y - height, r, g, b, a },
+            Vertex {
+                x,
+                y,
+                r,
+                g,
+                b,
+                a,
+                half_size_x,
+                half_size_y,
+                local_x: 0.,
+                local_y: 0.,
+                corners,
+            },
+            Vertex {
+                x,
+                y: y - height,
+                r,
+                g,
+                b,
+                a,
+                half_size_x,
+                half_size_y,
+                local_x: 0.,
+                local_y: rect.height,
+                corners,
+            },
+            Vertex {
+                x: x + width,
+                y,
+                r,
+                g,
+                b,
+                a,
+                half_size_x,
+                half_size_y,
+                local_x: rect.width,
+                local_y: 0.,
+                corners,
+            },
+            Vertex {
+                x: x + width,
+                y: y - height,
+                r,
+                g,
+                b,
+                a,
+                half_size_x,
+                half_size_y,
+                local_x: rect.width,
+                local_y: rect.height,
+                corners,
+            },
         ];
 
         // Append the vertices to form two triangles.
@@ -443,6 +688,12 @@ pub struct RectShaderProgram {
 
     /// Undercurl position.
     u_undercurl_position: Option<GLint>,
+
+    /// Corner radius for the rounded-background/outline shaders.
+    u_corner_radius: Option<GLint>,
+
+    /// Border thickness for the outline shader.
+    u_border_thickness: Option<GLint>,
 }
 
 impl RectShaderProgram {
@@ -450,7 +701,8 @@ impl RectShaderProgram {
         // XXX: This must be in-sync with fragment shader defines.
         let header = match kind {
             RectKind::RoundedBg => Some("#define DRAW_ROUNDED_BACKGROUND\n"),
-             RectKind::Undercurl => Some("#define DRAW_UNDER_CURL\n"),
+            RectKind::Outline => Some("#define DRAW_OUTLINE\n"),
+            RectKind::Undercurl => Some("#define DRAW_UNDER_CURL\n"),
             RectKind::UnderDotted => Some("#define DRAW_UNDER_DOTTED\n"),
             RectKind::UnderDashed => Some("#define DRAW_UNDER_DASHED\n"),
             _ => None,
@@ -465,6 +717,8 @@ impl RectShaderProgram {
             u_underline_position: program.get_uniform_location(cstr!("underlinePosition")).ok(),
             u_underline_thickness: program.get_uniform_location(cstr!("underlineThickness")).ok(),
             u_undercurl_position: program.get_uniform_location(cstr!("undercurlPosition")).ok(),
+            u_corner_radius: program.get_uniform_location(cstr!("cornerRadius")).ok(),
+            u_border_thickness: program.get_uniform_location(cstr!("borderThickness")).ok(),
             program,
         })
     }
@@ -473,7 +727,13 @@ impl RectShaderProgram {
         self.program.id()
     }
 
-    pub fn update_uniforms(&self, size_info: &SizeInfo, metrics: &Metrics) {
+    pub fn update_uniforms(
+        &self,
+        size_info: &SizeInfo,
+        metrics: &Metrics,
+        corner_radius: f32,
+        border_thickness: f32,
+    ) {
         let position = (0.5 * metrics.descent).abs();
         let underline_position = metrics.descent.abs() - metrics.underline_position.abs();
 
@@ -503,6 +763,12 @@ impl RectShaderProgram {
             if let Some(u_undercurl_position) = self.u_undercurl_position {
                 gl::Uniform1f(u_undercurl_position, position);
             }
+            if let Some(u_corner_radius) = self.u_corner_radius {
+                gl::Uniform1f(u_corner_radius, corner_radius);
+            }
+            if let Some(u_border_thickness) = self.u_border_thickness {
+                gl::Uniform1f(u_border_thickness, border_thickness);
+            }
         }
     }
 }